@@ -1,6 +1,8 @@
 // Sparse Merkle tree with batch updates
 
 use std::collections::HashMap;
+use std::io::{Read, Write};
+use rayon::prelude::*;
 use super::hasher::Hasher;
 use super::super::primitives::GetBits;
 
@@ -11,30 +13,259 @@ fn select<T>(condition: bool, a: T, b: T) -> (T, T) {
 
 
 // Lead index: 0 <= i < N
-type ItemIndex = usize;
+pub type ItemIndex = usize;
 
 // Tree of depth 0: 1 item (which is root), level 0 only
 // Tree of depth 1: 2 items, levels 0 and 1
 // Tree of depth N: 2 ^ N items, 0 <= level < depth
-type Depth = usize;
+pub type Depth = usize;
 
 // Nodes enumarated starting with index(root) = 1
-type NodeIndex = usize;
+pub type NodeIndex = usize;
 
-// Index of the node in the vector; slightly inefficient, won't be needed when rust gets non-lexical timelines
-type NodeRef = usize;
+// Reference to a node as understood by a `NodeStore`. For `VecStore` this is the
+// node's position in the backing vector; for `RocksStore` it is the `NodeIndex`
+// itself, since the store is keyed directly by it.
+pub type NodeRef = usize;
 
+// A `NodeStore` is `pub` so third-party backends can be written against it, so
+// the values its methods pass around need to be visible outside the crate too.
 #[derive(Debug, Clone)]
-struct Node<Hash> {
-    depth: Depth,
-    index: NodeIndex,
-    lhs: Option<NodeRef>,
-    rhs: Option<NodeRef>,
-    cached_hash: Option<Hash>,
+pub struct Node<Hash> {
+    pub depth: Depth,
+    pub index: NodeIndex,
+    pub lhs: Option<NodeRef>,
+    pub rhs: Option<NodeRef>,
+    pub cached_hash: Option<Hash>,
 }
 
+// Pluggable backing store for tree nodes, so a `SparseMerkleTree` can keep every
+// node in memory (`VecStore`) or page them in from disk (`RocksStore`) without the
+// tree itself knowing the difference.
+pub trait NodeStore<Hash: Clone> {
+    fn get(&self, node_ref: NodeRef) -> Node<Hash>;
+    fn put(&mut self, node: Node<Hash>) -> NodeRef;
+    fn update(&mut self, node_ref: NodeRef, node: Node<Hash>);
+
+    // Optional allocation hint; stores that don't benefit from it can ignore it.
+    fn reserve(&mut self, _additional: usize) {}
+}
+
+// In-memory node store: the original `Vec<Node<Hash>>` behavior.
 #[derive(Debug, Clone)]
-pub struct SparseMerkleTree<T: GetBits + Default, Hash: Clone, H: Hasher<Hash>>
+pub struct VecStore<Hash> {
+    nodes: Vec<Node<Hash>>,
+}
+
+impl<Hash> VecStore<Hash> {
+    pub fn new() -> Self {
+        VecStore{nodes: Vec::new()}
+    }
+}
+
+impl<Hash> Default for VecStore<Hash> {
+    fn default() -> Self { Self::new() }
+}
+
+impl<Hash: Clone> NodeStore<Hash> for VecStore<Hash> {
+    fn get(&self, node_ref: NodeRef) -> Node<Hash> {
+        self.nodes[node_ref].clone()
+    }
+
+    fn put(&mut self, node: Node<Hash>) -> NodeRef {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    fn update(&mut self, node_ref: NodeRef, node: Node<Hash>) {
+        self.nodes[node_ref] = node;
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.nodes.reserve(additional);
+    }
+}
+
+// Disk-backed node store over an embedded key-value store (RocksDB), so a tree with
+// tens of millions of slots can be opened, mutated and persisted without holding
+// every node in memory. Nodes are keyed by `NodeIndex` and serialized with a
+// leading type tag (0 = empty placeholder, 1 = internal, 2 = leaf) followed by
+// little-endian length-prefixed fields.
+pub struct RocksStore<Hash> {
+    db: rocksdb::DB,
+    _marker: std::marker::PhantomData<Hash>,
+}
+
+impl<Hash> RocksStore<Hash> {
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Self {
+        let db = rocksdb::DB::open_default(path).expect("failed to open node store");
+        RocksStore{db, _marker: std::marker::PhantomData}
+    }
+
+    pub fn flush(&self) {
+        self.db.flush().expect("failed to flush node store");
+    }
+
+    // Node keys, the metadata key and item keys below live in separate
+    // namespaces (leading tag byte) so a `NodeIndex` can never collide with
+    // the fixed metadata slot or an `ItemIndex`.
+    fn key(node_ref: NodeRef) -> [u8; 9] {
+        let mut key = [0u8; 9];
+        key[0] = 0; // node namespace
+        key[1..].copy_from_slice(&(node_ref as u64).to_le_bytes());
+        key
+    }
+
+    fn meta_key() -> [u8; 9] {
+        [1, 0, 0, 0, 0, 0, 0, 0, 0] // metadata namespace, single fixed slot
+    }
+
+    fn item_key(item_index: ItemIndex) -> [u8; 9] {
+        let mut key = [0u8; 9];
+        key[0] = 2; // item namespace
+        key[1..].copy_from_slice(&(item_index as u64).to_le_bytes());
+        key
+    }
+
+    // The root is always `NodeIndex` 1 (see `new_with_store`), so the only
+    // thing a reopened tree needs to recover besides its nodes and items is
+    // its own `tree_depth`. Written once, the first time a fresh tree is
+    // opened at `path`.
+    pub(crate) fn put_tree_depth(&mut self, tree_depth: Depth) {
+        self.db.put(&Self::meta_key(), &(tree_depth as u64).to_le_bytes())
+            .expect("node store write failed");
+    }
+
+    pub(crate) fn get_tree_depth(&self) -> Option<Depth> {
+        self.db.get(&Self::meta_key()).expect("node store read failed")
+            .map(|bytes| {
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(&bytes);
+                u64::from_le_bytes(buf) as Depth
+            })
+    }
+
+    // Write-through item persistence, keyed independently of the node
+    // topology so a mutation doesn't require touching (or holding in memory)
+    // anything beyond the single item it changes.
+    pub(crate) fn put_item(&mut self, item_index: ItemIndex, bytes: &[u8]) {
+        self.db.put(&Self::item_key(item_index), bytes).expect("node store write failed");
+    }
+
+    pub(crate) fn remove_item(&mut self, item_index: ItemIndex) {
+        self.db.delete(&Self::item_key(item_index)).expect("node store write failed");
+    }
+
+    // Only the populated items, not the tree's whole `capacity()` — bounded by
+    // `position()`, not by depth.
+    pub(crate) fn iter_items(&self) -> Vec<(ItemIndex, Vec<u8>)> {
+        self.db.iterator(rocksdb::IteratorMode::From(&[2u8], rocksdb::Direction::Forward))
+            .take_while(|(key, _)| key[0] == 2)
+            .map(|(key, value)| {
+                let mut idx_bytes = [0u8; 8];
+                idx_bytes.copy_from_slice(&key[1..9]);
+                (u64::from_le_bytes(idx_bytes) as ItemIndex, value.to_vec())
+            })
+            .collect()
+    }
+}
+
+impl<Hash> NodeStore<Hash> for RocksStore<Hash>
+    where Hash: Clone + Into<Vec<u8>> + From<Vec<u8>>,
+{
+    fn get(&self, node_ref: NodeRef) -> Node<Hash> {
+        let bytes = self.db.get(&Self::key(node_ref))
+            .expect("node store read failed")
+            .unwrap_or_else(|| panic!("node {} not found in store", node_ref));
+        decode_node(&bytes)
+    }
+
+    fn put(&mut self, node: Node<Hash>) -> NodeRef {
+        let node_ref = node.index;
+        self.update(node_ref, node);
+        node_ref
+    }
+
+    fn update(&mut self, node_ref: NodeRef, node: Node<Hash>) {
+        self.db.put(&Self::key(node_ref), &encode_node(&node)).expect("node store write failed");
+    }
+}
+
+fn encode_node<Hash: Into<Vec<u8>> + Clone>(node: &Node<Hash>) -> Vec<u8> {
+    let tag: u8 = if node.lhs.is_some() || node.rhs.is_some() {
+        1 // internal
+    } else if node.index == 1 {
+        0 // empty placeholder: the root of a tree with no items
+    } else {
+        2 // leaf
+    };
+
+    let mut out = Vec::new();
+    out.push(tag);
+    out.extend_from_slice(&(node.depth as u64).to_le_bytes());
+    out.extend_from_slice(&(node.index as u64).to_le_bytes());
+    encode_option_ref(&mut out, node.lhs);
+    encode_option_ref(&mut out, node.rhs);
+    match &node.cached_hash {
+        Some(hash) => {
+            let bytes: Vec<u8> = hash.clone().into();
+            out.push(1);
+            out.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+            out.extend_from_slice(&bytes);
+        }
+        None => out.push(0),
+    }
+    out
+}
+
+fn decode_node<Hash: From<Vec<u8>>>(bytes: &[u8]) -> Node<Hash> {
+    let mut pos = 1; // skip the type tag, it's only informative
+    let depth = read_u64(bytes, &mut pos) as Depth;
+    let index = read_u64(bytes, &mut pos) as NodeIndex;
+    let lhs = decode_option_ref(bytes, &mut pos);
+    let rhs = decode_option_ref(bytes, &mut pos);
+    let cached_hash = if bytes[pos] == 1 {
+        pos += 1;
+        let len = read_u64(bytes, &mut pos) as usize;
+        let hash_bytes = bytes[pos..pos + len].to_vec();
+        pos += len;
+        Some(Hash::from(hash_bytes))
+    } else {
+        None
+    };
+    let _ = pos;
+    Node{depth, index, lhs, rhs, cached_hash}
+}
+
+fn encode_option_ref(out: &mut Vec<u8>, r: Option<NodeRef>) {
+    match r {
+        Some(v) => {
+            out.push(1);
+            out.extend_from_slice(&(v as u64).to_le_bytes());
+        }
+        None => out.push(0),
+    }
+}
+
+fn decode_option_ref(bytes: &[u8], pos: &mut usize) -> Option<NodeRef> {
+    let tag = bytes[*pos];
+    *pos += 1;
+    if tag == 1 {
+        Some(read_u64(bytes, pos) as NodeRef)
+    } else {
+        None
+    }
+}
+
+fn read_u64(bytes: &[u8], pos: &mut usize) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[*pos..*pos + 8]);
+    *pos += 8;
+    u64::from_le_bytes(buf)
+}
+
+#[derive(Debug, Clone)]
+pub struct SparseMerkleTree<T: GetBits + Default, Hash: Clone, H: Hasher<Hash>, S: NodeStore<Hash> = VecStore<Hash>>
 {
     tree_depth: Depth,
     prehashed: Vec<Hash>,
@@ -43,21 +274,28 @@ pub struct SparseMerkleTree<T: GetBits + Default, Hash: Clone, H: Hasher<Hash>>
 
     // intermediate nodes
     root: NodeRef,
-    nodes: Vec<Node<Hash>>,
+    nodes: S,
 }
 
-impl<T, Hash, H> SparseMerkleTree< T, Hash, H>
+impl<T, Hash, H, S> SparseMerkleTree<T, Hash, H, S>
     where T: GetBits + Default,
           Hash: Clone,
           H: Hasher<Hash> + Default,
+          S: NodeStore<Hash>,
 {
 
-    pub fn new(tree_depth: Depth) -> Self {
+    pub fn new(tree_depth: Depth) -> Self
+        where S: Default
+    {
+        Self::new_with_store(tree_depth, S::default())
+    }
+
+    pub fn new_with_store(tree_depth: Depth, mut nodes: S) -> Self {
         assert!(tree_depth > 1);
         let hasher = H::default();
         let items = HashMap::new();
-        let mut nodes = Vec::new();
-        nodes.push(Node{
+
+        let root = nodes.put(Node{
             index: 1,
             depth: 0,
             lhs: None,
@@ -74,7 +312,7 @@ impl<T, Hash, H> SparseMerkleTree< T, Hash, H>
         }
         prehashed.reverse();
 
-        Self{tree_depth, prehashed, items, hasher, nodes, root: 0}
+        Self{tree_depth, prehashed, items, hasher, nodes, root}
     }
 
     #[inline(always)]
@@ -106,76 +344,104 @@ impl<T, Hash, H> SparseMerkleTree< T, Hash, H>
         let leaf_index = (1 << tree_depth) + item_index;
         //println!("\ninsert item_index = {}, leaf_index = {:?}", item_index, leaf_index);
 
+        if self.items.insert(item_index, item).is_some() {
+            // overwriting an already-linked leaf: its own `cached_hash` (and
+            // every ancestor's) is stale since it was computed from the old
+            // value, so walk the existing path down to it and invalidate each
+            // node in turn rather than only the freshly-created ones.
+            let mut cur_ref = self.root;
+            loop {
+                self.invalidate(cur_ref);
+                let cur = self.nodes.get(cur_ref);
+                if cur.depth == tree_depth {
+                    break;
+                }
+                let dir = (leaf_index & (1 << (tree_depth - cur.depth - 1))) > 0;
+                let link = if dir { cur.rhs } else { cur.lhs };
+                cur_ref = link.expect("item_index is in self.items, so it must already be linked");
+            }
+            return;
+        }
+
+        // inserting an item at a new index
         let leaf_ref = {
             self.insert_node(leaf_index, tree_depth, None, None)
         };
 
-        if let None = self.items.insert(item_index, item) {
-            // inserting an item at a new index
+        // traverse the tree
+        let mut cur_ref = self.root;
+        loop {
+            // the subtree rooted at `cur_ref` is about to gain the new leaf,
+            // so its cached hash is no longer valid
+            self.invalidate(cur_ref);
 
-            // traverse the tree
-            let mut cur_ref = self.root;
-            loop {
-                let cur = { self.nodes[cur_ref].clone() };
+            let cur = self.nodes.get(cur_ref);
 
-                //println!("cur_i = {:?}", cur_i);
-                //println!("cur_node = {:?}", cur_node);
+            //println!("cur_i = {:?}", cur_i);
+            //println!("cur_node = {:?}", cur_node);
 
-                let dir = (leaf_index & (1 << (tree_depth - cur.depth - 1))) > 0;
-                //println!("dir = {:?}", dir);
-                let mut link = if dir { cur.rhs } else { cur.lhs };
-                if let Some(next_ref) = link {
-                    let next = { self.nodes[next_ref].clone() };
-                    let leaf_index_normalized = leaf_index >> (tree_depth - next.depth);
-                    //println!("next = {}, leaf_index_normalized = {:?}, next_depth = {:?}", next, leaf_index_normalized, next_depth);
-
-                    if leaf_index_normalized == next.index {
-                        // invalidate cash and follow the link
-                        //self.nodes[cur_ref].cached_hash
-                        cur_ref = next_ref;
-                        continue;
-                    } else {
-                        // split at intersection
-                        let inter_index = {
-                            // intersection index is the longest common prefix
-                            let mut i = leaf_index_normalized;
-                            let mut j = next.index;
-                            while i != j {
-                                i >>= 1;
-                                j >>= 1;
-                            }
-                            i
-                        };
-                        //println!("intersection = {:?}", intersection_i);
+            let dir = (leaf_index & (1 << (tree_depth - cur.depth - 1))) > 0;
+            //println!("dir = {:?}", dir);
+            let link = if dir { cur.rhs } else { cur.lhs };
+            if let Some(next_ref) = link {
+                let next = self.nodes.get(next_ref);
+                let leaf_index_normalized = leaf_index >> (tree_depth - next.depth);
+                //println!("next = {}, leaf_index_normalized = {:?}, next_depth = {:?}", next, leaf_index_normalized, next_depth);
 
-                        let (lhs, rhs) = select(leaf_index_normalized > next.index, Some(next_ref), Some(leaf_ref));
-                        let inter_ref = self.insert_node(inter_index, Self::depth(inter_index), lhs, rhs);
-                        //println!("node[{}] = {:?}", intersection_i, inter_node);
-                        self.add_child(cur_ref, dir, inter_ref);
-                        break;
-                    }
+                if leaf_index_normalized == next.index {
+                    // follow the link toward the leaf
+                    cur_ref = next_ref;
+                    continue;
                 } else {
-                    // insert the leaf node and update cur
-                    self.add_child(cur_ref, dir, leaf_ref);
+                    // split at intersection
+                    let inter_index = {
+                        // intersection index is the longest common prefix
+                        let mut i = leaf_index_normalized;
+                        let mut j = next.index;
+                        while i != j {
+                            i >>= 1;
+                            j >>= 1;
+                        }
+                        i
+                    };
+                    //println!("intersection = {:?}", intersection_i);
+
+                    let (lhs, rhs) = select(leaf_index_normalized > next.index, Some(next_ref), Some(leaf_ref));
+                    let inter_ref = self.insert_node(inter_index, Self::depth(inter_index), lhs, rhs);
+                    //println!("node[{}] = {:?}", intersection_i, inter_node);
+                    self.add_child(cur_ref, dir, inter_ref);
                     break;
                 }
+            } else {
+                // insert the leaf node and update cur
+                self.add_child(cur_ref, dir, leaf_ref);
+                break;
             }
         }
+    }
 
+    fn invalidate(&mut self, r: NodeRef) {
+        let mut node = self.nodes.get(r);
+        node.cached_hash = None;
+        self.nodes.update(r, node);
     }
 
     fn add_child(&mut self, r: NodeRef, dir: bool, child: NodeRef) {
-        let node = &mut self.nodes[r];
+        self.set_child(r, dir, Some(child));
+    }
+
+    fn set_child(&mut self, r: NodeRef, dir: bool, child: Option<NodeRef>) {
+        let mut node = self.nodes.get(r);
         if dir {
-            node.rhs = Some(child);
+            node.rhs = child;
         } else {
-            node.lhs = Some(child);
+            node.lhs = child;
         }
+        self.nodes.update(r, node);
     }
 
     fn insert_node(&mut self, index: NodeIndex, depth: Depth, lhs: Option<NodeRef>, rhs: Option<NodeRef>) -> NodeRef {
-        self.nodes.push(Node{index, depth, lhs, rhs, cached_hash: None});
-        self.nodes.len() - 1
+        self.nodes.put(Node{index, depth, lhs, rhs, cached_hash: None})
     }
 
     // optimization to reduce num of mem allocs
@@ -184,19 +450,50 @@ impl<T, Hash, H> SparseMerkleTree< T, Hash, H>
         self.nodes.reserve(2 * n);
     }
 
-    fn hash_line(&mut self, from: Option<NodeRef>, to_ref: NodeRef, dir: bool) -> Hash {
-        //println!("hash_line {:?} {} {}", from, to, dir);
-        let to = &self.nodes[to_ref].clone();
+    // Folds `from`'s hash up to what it would be if it were a direct child of a
+    // node at `to_depth`. Each skipped level needs its own direction: `from`'s
+    // `index` encodes the whole root-to-`from` path (standard binary-heap
+    // indexing), so the bit for the edge connecting depth `d` to `d + 1` is
+    // `(from.index >> (from.depth - d - 1)) & 1`, independent of which level of
+    // the chain we're folding through.
+    fn hash_line(&mut self, from: Option<NodeRef>, to_depth: Depth) -> Hash {
+        //println!("hash_line {:?} {}", from, to_depth);
         match from {
-            None => self.prehashed[to.depth + 1].clone(),
+            None => self.prehashed[to_depth + 1].clone(),
             Some(from_ref) => {
-                let from = self.nodes[from_ref].clone();
+                let from = self.nodes.get(from_ref);
                 let mut cur_hash = self.get_hash(from_ref);
                 let mut cur_depth = from.depth - 1;
-                while cur_depth > to.depth {
+                while cur_depth > to_depth {
                     //println!("cur_depth = {}", cur_depth);
                     unsafe { HC += 1; }
-                    let (lhs, rhs) = select(!dir, cur_hash, self.prehashed[cur_depth + 1].clone());
+                    let bit = (from.index >> (from.depth - cur_depth - 1)) & 1 == 1;
+                    let (lhs, rhs) = select(bit, self.prehashed[cur_depth + 1].clone(), cur_hash);
+                    cur_hash = self.hasher.compress(&lhs, &rhs, self.tree_depth - cur_depth - 1);
+                    cur_depth -= 1;
+                }
+                cur_hash
+            }
+        }
+    }
+
+    // Same fold as `hash_line`, but read-only: it assumes `from`'s hash (and every
+    // node on the way up to `to_depth`) is already cached, which holds when called
+    // from `root_hash_parallel` after its deeper layer has been hashed. Like
+    // `hash_line`, each skipped level needs the bit for its own edge, read from
+    // `from`'s index rather than a single direction fixed across the whole fold.
+    fn hash_line_cached(&self, from: Option<NodeRef>, to_depth: Depth) -> Hash {
+        match from {
+            None => self.prehashed[to_depth + 1].clone(),
+            Some(from_ref) => {
+                let from = self.nodes.get(from_ref);
+                let mut cur_hash = from.cached_hash
+                    .clone()
+                    .expect("node hash must already be cached by a deeper parallel pass");
+                let mut cur_depth = from.depth - 1;
+                while cur_depth > to_depth {
+                    let bit = (from.index >> (from.depth - cur_depth - 1)) & 1 == 1;
+                    let (lhs, rhs) = select(bit, self.prehashed[cur_depth + 1].clone(), cur_hash);
                     cur_hash = self.hasher.compress(&lhs, &rhs, self.tree_depth - cur_depth - 1);
                     cur_depth -= 1;
                 }
@@ -207,35 +504,530 @@ impl<T, Hash, H> SparseMerkleTree< T, Hash, H>
 
     fn get_hash(&mut self, node_ref: NodeRef) -> Hash {
         //println!("get_hash {}", index);
-        let (lhs, rhs, level) = {
-            let node = &self.nodes[node_ref];
+        let node = self.nodes.get(node_ref);
 
-            if let Some(cached) = &node.cached_hash {
-                return cached.clone()
-            }
+        if let Some(cached) = &node.cached_hash {
+            return cached.clone()
+        }
 
-            if node.depth == self.tree_depth {
-                // leaf node: return item hash
-                let item_index = node.index - (1 << self.tree_depth);
-                //println!("item_index = {}", item_index);
-                unsafe { HN += 1; }
-                return self.hasher.hash_bits(self.items[&item_index].get_bits_le())
-            }
+        if node.depth == self.tree_depth {
+            // leaf node: return item hash
+            let item_index = node.index - (1 << self.tree_depth);
+            //println!("item_index = {}", item_index);
+            unsafe { HN += 1; }
+            return self.hasher.hash_bits(self.items[&item_index].get_bits_le())
+        }
 
-            let level = self.tree_depth - node.depth - 1;
-            (node.lhs, node.rhs, level)
-        };
-        let lhs = self.hash_line(lhs, node_ref, false);
-        let rhs = self.hash_line(rhs, node_ref, true);
+        let level = self.tree_depth - node.depth - 1;
+        let lhs = self.hash_line(node.lhs, node.depth);
+        let rhs = self.hash_line(node.rhs, node.depth);
         let hash = self.hasher.compress(&lhs, &rhs, level);
-        self.nodes[node_ref].cached_hash = Some(hash.clone());
+
+        let mut node = self.nodes.get(node_ref);
+        node.cached_hash = Some(hash.clone());
+        self.nodes.update(node_ref, node);
         hash
     }
 
     pub fn root_hash(&mut self) -> Hash {
-        self.get_hash(0)
+        self.get_hash(self.root)
     }
 
+    // Returns the leaf value together with the `tree_depth` sibling hashes needed to
+    // recompute the root, ordered leaf -> root.
+    pub fn merkle_path(&mut self, item_index: ItemIndex) -> (T, Vec<Hash>)
+        where T: Clone
+    {
+        assert!(item_index < self.capacity());
+        let tree_depth = self.tree_depth;
+        let leaf_index = (1 << tree_depth) + item_index;
+
+        let mut path = Vec::with_capacity(tree_depth);
+        let mut cur_ref = Some(self.root);
+        for cur_depth in 0..tree_depth {
+            let sibling = match cur_ref {
+                None => self.prehashed[cur_depth + 1].clone(),
+                Some(r) => {
+                    let cur = self.nodes.get(r);
+                    if cur.depth > cur_depth {
+                        // `r` belongs to a node further down a single-child chain
+                        // than this level. That only means the sibling here is
+                        // empty if the chain's own path still agrees with the
+                        // query at this level (mirrors `insert`'s
+                        // `leaf_index_normalized == next.index` check, done
+                        // incrementally one bit at a time). Once it disagrees,
+                        // `r`'s whole subtree IS the sibling, and nothing real
+                        // remains on the query's side below this point.
+                        let query_bit = (leaf_index & (1 << (tree_depth - cur_depth - 1))) > 0;
+                        let real_bit = (cur.index & (1 << (cur.depth - cur_depth - 1))) > 0;
+                        if query_bit == real_bit {
+                            self.prehashed[cur_depth + 1].clone()
+                        } else {
+                            let sibling = self.hash_line(Some(r), cur_depth);
+                            cur_ref = None;
+                            sibling
+                        }
+                    } else {
+                        let dir = (leaf_index & (1 << (tree_depth - cur.depth - 1))) > 0;
+                        let (on, off) = if dir { (cur.rhs, cur.lhs) } else { (cur.lhs, cur.rhs) };
+                        let sibling = match off {
+                            Some(off_ref) => self.hash_line(Some(off_ref), cur.depth),
+                            None => self.prehashed[cur.depth + 1].clone(),
+                        };
+                        cur_ref = on;
+                        sibling
+                    }
+                }
+            };
+            path.push(sibling);
+        }
+        path.reverse();
+
+        let item = self.items.get(&item_index).cloned().unwrap_or_default();
+        (item, path)
+    }
+
+    // Clears `item_index` back to the empty (default) leaf. A no-op if the index
+    // was never set.
+    pub fn remove(&mut self, item_index: ItemIndex) {
+        assert!(item_index < self.capacity());
+        if self.items.remove(&item_index).is_none() {
+            return;
+        }
+        let tree_depth = self.tree_depth;
+        let leaf_index = (1 << tree_depth) + item_index;
+
+        // Re-walk the path from the root the same way `insert` does, remembering
+        // every node visited so we can collapse a now-redundant split node.
+        let mut chain: Vec<(NodeRef, bool)> = Vec::new();
+        let mut cur_ref = self.root;
+        let leaf_dir = loop {
+            let cur = self.nodes.get(cur_ref);
+            let dir = (leaf_index & (1 << (tree_depth - cur.depth - 1))) > 0;
+            let link = if dir { cur.rhs } else { cur.lhs };
+            match link {
+                Some(next_ref) => {
+                    if self.nodes.get(next_ref).depth == tree_depth {
+                        break dir;
+                    }
+                    chain.push((cur_ref, dir));
+                    cur_ref = next_ref;
+                }
+                None => unreachable!("item_index is in self.items, so it must already be linked"),
+            }
+        };
+
+        // unlink the leaf
+        self.set_child(cur_ref, leaf_dir, None);
+        self.invalidate(cur_ref);
+
+        if cur_ref != self.root {
+            let node = self.nodes.get(cur_ref);
+            // a split node always starts with two children (see `insert`'s
+            // intersection case), so losing one leaves exactly one behind
+            if let Some(remaining_ref) = node.lhs.or(node.rhs) {
+                let &(grandparent_ref, grandparent_dir) = chain.last().unwrap();
+                self.set_child(grandparent_ref, grandparent_dir, Some(remaining_ref));
+            }
+        }
+
+        // the shape of the whole path down to the removed leaf changed, so the
+        // cached hashes of its ancestors are no longer valid
+        for &(node_ref, _) in &chain {
+            self.invalidate(node_ref);
+        }
+    }
+
+    // Applies a batch of removals followed by a batch of sets as a single
+    // transaction: every index is validated against `capacity()` up front, so a
+    // single bad entry leaves the tree untouched.
+    pub fn remove_indices_and_set_leaves(&mut self, remove: &[ItemIndex], set: &[(ItemIndex, T)])
+        where T: Clone
+    {
+        let capacity = self.capacity();
+        let end_index = set.iter().map(|&(index, _)| index + 1).max().unwrap_or(0);
+        assert!(end_index <= capacity, "set index out of range");
+        assert!(remove.iter().all(|&index| index < capacity), "remove index out of range");
+
+        for &item_index in remove {
+            self.remove(item_index);
+        }
+        for (item_index, item) in set {
+            self.insert(*item_index, item.clone());
+        }
+    }
+
+    // Number of occupied leaves.
+    pub fn position(&self) -> usize {
+        self.items.len()
+    }
+
+    // Read-only counterpart to `get_hash`/`hash_line`: recomputes a node's hash
+    // without caching it, so it can be called from `&self` methods like
+    // `clone_trimmed`.
+    fn compute_hash(&self, node_ref: NodeRef) -> Hash {
+        let node = self.nodes.get(node_ref);
+        if let Some(cached) = &node.cached_hash {
+            return cached.clone();
+        }
+        if node.depth == self.tree_depth {
+            let item_index = node.index - (1 << self.tree_depth);
+            return self.hasher.hash_bits(self.items[&item_index].get_bits_le());
+        }
+        let level = self.tree_depth - node.depth - 1;
+        let lhs = self.compute_hash_line(node.lhs, node.depth);
+        let rhs = self.compute_hash_line(node.rhs, node.depth);
+        self.hasher.compress(&lhs, &rhs, level)
+    }
+
+    // Same fold as `hash_line`: each skipped level needs the bit for its own
+    // edge, read from the folded node's index, not a single direction fixed
+    // across the whole chain.
+    fn compute_hash_line(&self, from: Option<NodeRef>, to_depth: Depth) -> Hash {
+        match from {
+            None => self.prehashed[to_depth + 1].clone(),
+            Some(from_ref) => {
+                let from = self.nodes.get(from_ref);
+                let mut cur_hash = self.compute_hash(from_ref);
+                let mut cur_depth = from.depth - 1;
+                while cur_depth > to_depth {
+                    let bit = (from.index >> (from.depth - cur_depth - 1)) & 1 == 1;
+                    let (lhs, rhs) = select(bit, self.prehashed[cur_depth + 1].clone(), cur_hash);
+                    cur_hash = self.hasher.compress(&lhs, &rhs, self.tree_depth - cur_depth - 1);
+                    cur_depth -= 1;
+                }
+                cur_hash
+            }
+        }
+    }
+
+    // Returns a tree that reproduces the same `root_hash` but carries only the
+    // top `keep_depth` levels: every node below that frontier is dropped and
+    // replaced by a single stub node holding its subtree's cached hash. Useful
+    // for handing a light client just enough of the tree to verify a
+    // `merkle_path` against the current root.
+    pub fn clone_trimmed(&self, keep_depth: Depth) -> Self
+        where S: Default
+    {
+        assert!(keep_depth <= self.tree_depth);
+        let mut nodes = S::default();
+        let root = self.copy_trimmed(self.root, keep_depth, &mut nodes);
+
+        Self{
+            tree_depth: self.tree_depth,
+            prehashed: self.prehashed.clone(),
+            items: HashMap::new(),
+            hasher: H::default(),
+            root,
+            nodes,
+        }
+    }
+
+    fn copy_trimmed(&self, node_ref: NodeRef, keep_depth: Depth, out: &mut S) -> NodeRef {
+        let node = self.nodes.get(node_ref);
+        if node.depth >= keep_depth {
+            // a node below the frontier sits at the end of a single-child chain,
+            // so fold its hash up through the skipped levels to `keep_depth`
+            let hash = if node.depth == keep_depth {
+                self.compute_hash(node_ref)
+            } else {
+                self.compute_hash_line(Some(node_ref), keep_depth - 1)
+            };
+            let index = node.index >> (node.depth - keep_depth);
+            return out.put(Node{index, depth: keep_depth, lhs: None, rhs: None, cached_hash: Some(hash)});
+        }
+        let lhs = node.lhs.map(|r| self.copy_trimmed(r, keep_depth, out));
+        let rhs = node.rhs.map(|r| self.copy_trimmed(r, keep_depth, out));
+        out.put(Node{index: node.index, depth: node.depth, lhs, rhs, cached_hash: node.cached_hash.clone()})
+    }
+
+    // Writes `tree_depth`, the populated items and the live node topology
+    // (reachable from the root) using little-endian length-prefixed encoding.
+    pub fn serialize<W: Write>(&mut self, w: &mut W)
+        where T: Clone + Into<Vec<u8>>,
+              Hash: Into<Vec<u8>>,
+    {
+        w.write_all(&(self.tree_depth as u64).to_le_bytes()).expect("write failed");
+
+        w.write_all(&(self.items.len() as u64).to_le_bytes()).expect("write failed");
+        for (index, item) in &self.items {
+            w.write_all(&(*index as u64).to_le_bytes()).expect("write failed");
+            write_bytes(w, item.clone().into());
+        }
+
+        self.serialize_node(self.root, w);
+    }
+
+    fn serialize_node<W: Write>(&self, node_ref: NodeRef, w: &mut W)
+        where Hash: Into<Vec<u8>>,
+    {
+        let node = self.nodes.get(node_ref);
+        w.write_all(&(node.depth as u64).to_le_bytes()).expect("write failed");
+        w.write_all(&(node.index as u64).to_le_bytes()).expect("write failed");
+        match &node.cached_hash {
+            Some(hash) => {
+                w.write_all(&[1u8]).expect("write failed");
+                write_bytes(w, hash.clone().into());
+            }
+            None => w.write_all(&[0u8]).expect("write failed"),
+        }
+        self.serialize_link(node.lhs, w);
+        self.serialize_link(node.rhs, w);
+    }
+
+    fn serialize_link<W: Write>(&self, link: Option<NodeRef>, w: &mut W)
+        where Hash: Into<Vec<u8>>,
+    {
+        match link {
+            Some(child_ref) => {
+                w.write_all(&[1u8]).expect("write failed");
+                self.serialize_node(child_ref, w);
+            }
+            None => w.write_all(&[0u8]).expect("write failed"),
+        }
+    }
+
+    // Reconstructs a tree previously written by `serialize`.
+    pub fn deserialize<R: Read>(r: &mut R) -> Self
+        where T: Clone + From<Vec<u8>>,
+              Hash: From<Vec<u8>>,
+              S: Default,
+    {
+        Self::deserialize_into(r, S::default())
+    }
+
+    // Same as `deserialize`, but writes into an already-constructed store
+    // instead of creating one; `deserialize` is its only caller.
+    fn deserialize_into<R: Read>(r: &mut R, mut nodes: S) -> Self
+        where T: Clone + From<Vec<u8>>,
+              Hash: From<Vec<u8>>,
+    {
+        let tree_depth = read_u64_from(r) as Depth;
+        let hasher = H::default();
+
+        let mut items = HashMap::new();
+        let item_count = read_u64_from(r);
+        for _ in 0..item_count {
+            let index = read_u64_from(r) as ItemIndex;
+            let item = T::from(read_bytes(r));
+            items.insert(index, item);
+        }
+
+        let root = Self::deserialize_node(r, &mut nodes);
+
+        let mut prehashed = Vec::with_capacity(tree_depth);
+        let mut cur = hasher.hash_bits(T::default().get_bits_le());
+        prehashed.push(cur.clone());
+        for i in 0..tree_depth {
+            cur = hasher.compress(&cur, &cur, i);
+            prehashed.push(cur.clone());
+        }
+        prehashed.reverse();
+
+        Self{tree_depth, prehashed, items, hasher, root, nodes}
+    }
+
+    fn deserialize_node<R: Read>(r: &mut R, nodes: &mut S) -> NodeRef
+        where Hash: From<Vec<u8>>,
+    {
+        let depth = read_u64_from(r) as Depth;
+        let index = read_u64_from(r) as NodeIndex;
+        let mut tag = [0u8; 1];
+        r.read_exact(&mut tag).expect("read failed");
+        let cached_hash = if tag[0] == 1 {
+            Some(Hash::from(read_bytes(r)))
+        } else {
+            None
+        };
+        let lhs = Self::deserialize_link(r, nodes);
+        let rhs = Self::deserialize_link(r, nodes);
+        nodes.put(Node{depth, index, lhs, rhs, cached_hash})
+    }
+
+    fn deserialize_link<R: Read>(r: &mut R, nodes: &mut S) -> Option<NodeRef>
+        where Hash: From<Vec<u8>>,
+    {
+        let mut tag = [0u8; 1];
+        r.read_exact(&mut tag).expect("read failed");
+        if tag[0] == 1 {
+            Some(Self::deserialize_node(r, nodes))
+        } else {
+            None
+        }
+    }
+
+}
+
+fn write_bytes<W: Write>(w: &mut W, bytes: Vec<u8>) {
+    w.write_all(&(bytes.len() as u64).to_le_bytes()).expect("write failed");
+    w.write_all(&bytes).expect("write failed");
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> Vec<u8> {
+    let len = read_u64_from(r) as usize;
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf).expect("read failed");
+    buf
+}
+
+fn read_u64_from<R: Read>(r: &mut R) -> u64 {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf).expect("read failed");
+    u64::from_le_bytes(buf)
+}
+
+impl<T, Hash, H> SparseMerkleTree<T, Hash, H, RocksStore<Hash>>
+    where T: GetBits + Default,
+          Hash: Clone + Into<Vec<u8>> + From<Vec<u8>>,
+          H: Hasher<Hash> + Default,
+{
+    // Opens a disk-backed tree rooted at `path`. If `path` already holds a
+    // tree (i.e. it has a recorded `tree_depth`), its nodes and items are
+    // already on disk from ordinary `insert_persisted`/`remove_persisted`
+    // write-throughs, so they're picked straight back up; otherwise a fresh
+    // empty tree of `tree_depth` is created.
+    pub fn open<P: AsRef<std::path::Path>>(tree_depth: Depth, path: P) -> Self
+        where T: From<Vec<u8>>,
+    {
+        let mut store = RocksStore::open(path);
+        match store.get_tree_depth() {
+            Some(depth) => Self::reopen(depth, store),
+            None => {
+                store.put_tree_depth(tree_depth);
+                Self::new_with_store(tree_depth, store)
+            }
+        }
+    }
+
+    // Rebuilds the tree struct around an already-populated store: the root is
+    // always `NodeIndex` 1 (see `new_with_store`), so only `items` needs
+    // reconstructing, bounded by however many are actually populated rather
+    // than the tree's whole capacity.
+    fn reopen(tree_depth: Depth, store: RocksStore<Hash>) -> Self
+        where T: From<Vec<u8>>,
+    {
+        let hasher = H::default();
+        let items: HashMap<ItemIndex, T> = store.iter_items()
+            .into_iter()
+            .map(|(item_index, bytes)| (item_index, T::from(bytes)))
+            .collect();
+
+        let mut prehashed = Vec::with_capacity(tree_depth);
+        let mut cur = hasher.hash_bits(T::default().get_bits_le());
+        prehashed.push(cur.clone());
+        for i in 0..tree_depth {
+            cur = hasher.compress(&cur, &cur, i);
+            prehashed.push(cur.clone());
+        }
+        prehashed.reverse();
+
+        Self{tree_depth, prehashed, items, hasher, root: 1, nodes: store}
+    }
+
+    // `insert`/`remove` already write each touched node straight through to
+    // disk; these write-through the item's own value alongside it, so a tree
+    // reopened with `open` recovers without ever needing a whole-tree
+    // snapshot.
+    pub fn insert_persisted(&mut self, item_index: ItemIndex, item: T)
+        where T: Clone + Into<Vec<u8>>,
+    {
+        self.nodes.put_item(item_index, &item.clone().into());
+        self.insert(item_index, item);
+    }
+
+    pub fn remove_persisted(&mut self, item_index: ItemIndex) {
+        self.nodes.remove_item(item_index);
+        self.remove(item_index);
+    }
+
+    // Forces the store's buffered writes to disk; doesn't need to touch
+    // anything beyond that, since every mutation is already persisted
+    // incrementally rather than batched into a snapshot here.
+    pub fn flush(&mut self) {
+        self.nodes.flush();
+    }
+}
+
+impl<T, Hash, H> SparseMerkleTree<T, Hash, H, VecStore<Hash>>
+    where T: GetBits + Default + Sync,
+          Hash: Clone + Send + Sync,
+          H: Hasher<Hash> + Default + Sync,
+{
+    // Walks the nodes actually reachable from `root`, grouped by depth.
+    // Scanning the whole backing store would also pick up nodes `remove()` has
+    // unlinked, which stay there as garbage but no longer belong to the tree.
+    fn collect_live(&self, node_ref: NodeRef, by_depth: &mut Vec<Vec<NodeRef>>) {
+        let node = self.nodes.get(node_ref);
+        by_depth[node.depth].push(node_ref);
+        if let Some(lhs) = node.lhs {
+            self.collect_live(lhs, by_depth);
+        }
+        if let Some(rhs) = node.rhs {
+            self.collect_live(rhs, by_depth);
+        }
+    }
+
+    // Hashes every live subtree layer by layer, deepest occupied level first,
+    // hashing all nodes within a layer concurrently with rayon. `H::compress` is
+    // pure, so this is safe as long as every level `d+1` hash is finalized (and
+    // cached) before level `d` begins, which this does by walking depths in
+    // strictly decreasing order.
+    pub fn root_hash_parallel(&mut self) -> Hash {
+        let tree_depth = self.tree_depth;
+        let root = self.root;
+        let mut by_depth: Vec<Vec<NodeRef>> = vec![Vec::new(); tree_depth + 1];
+        self.collect_live(root, &mut by_depth);
+
+        // leaves have no children: hash them straight from the item they store
+        let leaf_hashes: Vec<(NodeRef, Hash)> = by_depth[tree_depth].par_iter()
+            .map(|&r| {
+                let node = self.nodes.get(r);
+                let item_index = node.index - (1 << tree_depth);
+                (r, self.hasher.hash_bits(self.items[&item_index].get_bits_le()))
+            })
+            .collect();
+        for (r, hash) in leaf_hashes {
+            let mut node = self.nodes.get(r);
+            node.cached_hash = Some(hash);
+            self.nodes.update(r, node);
+        }
+
+        for depth in (0..tree_depth).rev() {
+            let level = tree_depth - depth - 1;
+            let hashes: Vec<(NodeRef, Hash)> = by_depth[depth].par_iter()
+                .map(|&r| {
+                    let node = self.nodes.get(r);
+                    if let Some(cached) = &node.cached_hash {
+                        return (r, cached.clone());
+                    }
+                    let lhs = self.hash_line_cached(node.lhs, node.depth);
+                    let rhs = self.hash_line_cached(node.rhs, node.depth);
+                    (r, self.hasher.compress(&lhs, &rhs, level))
+                })
+                .collect();
+            for (r, hash) in hashes {
+                let mut node = self.nodes.get(r);
+                node.cached_hash = Some(hash);
+                self.nodes.update(r, node);
+            }
+        }
+
+        self.get_hash(self.root)
+    }
+}
+
+// Verifies a `merkle_path` against `root` without needing access to the tree itself.
+pub fn verify_path<Hash, H>(root: &Hash, item_index: ItemIndex, leaf_bits: Vec<bool>, path: &[Hash], hasher: &H) -> bool
+    where Hash: Clone + PartialEq,
+          H: Hasher<Hash>,
+{
+    let mut cur_hash = hasher.hash_bits(leaf_bits);
+    for (level, sibling) in path.iter().enumerate() {
+        let dir = (item_index & (1 << level)) > 0;
+        let (lhs, rhs) = if dir { (sibling, &cur_hash) } else { (&cur_hash, sibling) };
+        cur_hash = hasher.compress(lhs, rhs, level);
+    }
+    cur_hash == *root
 }
 
 static mut HN: usize = 0;
@@ -248,7 +1040,7 @@ mod tests {
     #[derive(Debug)]
     struct TestHasher {}
 
-    #[derive(Debug)]
+    #[derive(Debug, Clone)]
     struct TestLeaf(u64);
 
     impl Default for TestLeaf {
@@ -347,15 +1139,197 @@ mod tests {
         assert_eq!(tree.root_hash(), 749601611);
     }
 
+    #[test]
+    fn test_merkle_path() {
+        let mut tree = TestSMT::new(3);
+
+        tree.insert(0, TestLeaf(1));
+        tree.insert(3, TestLeaf(2));
+        tree.insert(5, TestLeaf(3));
+
+        let root = tree.root_hash();
+
+        for index in 0..tree.capacity() {
+            let (leaf, path) = tree.merkle_path(index);
+            assert_eq!(path.len(), 3);
+            assert!(verify_path(&root, index, leaf.get_bits_le(), &path, &TestHasher{}));
+        }
+
+        // a wrong index must not verify
+        let (_, path) = tree.merkle_path(0);
+        assert!(!verify_path(&root, 1, TestLeaf(1).get_bits_le(), &path, &TestHasher{}));
+    }
+
+    #[test]
+    fn test_remove_matches_never_inserted() {
+        let mut untouched = TestSMT::new(3);
+        untouched.insert(3, TestLeaf(2));
+        let expected_root = untouched.root_hash();
+
+        let mut tree = TestSMT::new(3);
+        tree.insert(0, TestLeaf(1));
+        tree.insert(3, TestLeaf(2));
+        tree.remove(0);
+
+        assert_eq!(tree.root_hash(), expected_root);
+    }
+
+    #[test]
+    fn test_remove_then_reinsert_matches_original_root() {
+        let mut tree = TestSMT::new(3);
+        tree.insert(0, TestLeaf(1));
+        tree.insert(3, TestLeaf(2));
+        tree.insert(5, TestLeaf(3));
+        let original_root = tree.root_hash();
+
+        tree.remove(3);
+        tree.insert(3, TestLeaf(2));
+
+        assert_eq!(tree.root_hash(), original_root);
+    }
+
+    #[test]
+    fn test_remove_indices_and_set_leaves_rejects_out_of_range() {
+        let mut tree = TestSMT::new(3);
+        tree.insert(0, TestLeaf(1));
+        let original_root = tree.root_hash();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            tree.remove_indices_and_set_leaves(&[0], &[(100, TestLeaf(9))]);
+        }));
+        assert!(result.is_err());
+        assert_eq!(tree.root_hash(), original_root);
+    }
+
+    #[test]
+    fn test_root_hash_reflects_inserts_after_caching() {
+        let mut tree = TestSMT::new(3);
+        tree.insert(0, TestLeaf(1));
+        let root_before = tree.root_hash();
+
+        tree.insert(3, TestLeaf(2));
+        let root_after = tree.root_hash();
+
+        assert_ne!(root_before, root_after);
+
+        let mut fresh = TestSMT::new(3);
+        fresh.insert(0, TestLeaf(1));
+        fresh.insert(3, TestLeaf(2));
+        assert_eq!(root_after, fresh.root_hash());
+    }
+
+    #[test]
+    fn test_root_hash_parallel_matches_root_hash() {
+        let mut tree = TestSMT::new(10);
+        for i in 0..50 {
+            tree.insert(i * 7 % tree.capacity(), TestLeaf(i as u64));
+        }
+
+        let serial_root = {
+            let mut reference = TestSMT::new(10);
+            for i in 0..50 {
+                reference.insert(i * 7 % reference.capacity(), TestLeaf(i as u64));
+            }
+            reference.root_hash()
+        };
+
+        assert_eq!(tree.root_hash_parallel(), serial_root);
+    }
+
+    #[test]
+    fn test_position_and_clone_trimmed() {
+        let mut tree = TestSMT::new(5);
+        assert_eq!(tree.position(), 0);
+
+        tree.insert(0, TestLeaf(1));
+        tree.insert(3, TestLeaf(2));
+        tree.insert(17, TestLeaf(3));
+        assert_eq!(tree.position(), 3);
+
+        let root = tree.root_hash();
+        let mut trimmed = tree.clone_trimmed(2);
+        assert_eq!(trimmed.position(), 0);
+        assert_eq!(trimmed.root_hash(), root);
+    }
+
+    // A `Hash` wrapping a `u64` that, unlike `u64` itself, this crate is allowed
+    // to implement `Into`/`From<Vec<u8>>` for (needed to exercise `serialize`).
+    #[derive(Debug, Clone, PartialEq)]
+    struct Hash64(u64);
+
+    impl Into<Vec<u8>> for Hash64 {
+        fn into(self) -> Vec<u8> {
+            self.0.to_le_bytes().to_vec()
+        }
+    }
+
+    impl From<Vec<u8>> for Hash64 {
+        fn from(bytes: Vec<u8>) -> Self {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&bytes);
+            Hash64(u64::from_le_bytes(buf))
+        }
+    }
+
+    impl Hasher<Hash64> for TestHasher {
+        fn hash_bits<I: IntoIterator<Item=bool>>(&self, value: I) -> Hash64 {
+            Hash64(Hasher::<u64>::hash_bits(self, value))
+        }
+
+        fn compress(&self, lhs: &Hash64, rhs: &Hash64, i: usize) -> Hash64 {
+            Hash64(Hasher::<u64>::compress(self, &lhs.0, &rhs.0, i))
+        }
+    }
+
+    impl Into<Vec<u8>> for TestLeaf {
+        fn into(self) -> Vec<u8> {
+            self.0.to_le_bytes().to_vec()
+        }
+    }
+
+    impl From<Vec<u8>> for TestLeaf {
+        fn from(bytes: Vec<u8>) -> Self {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&bytes);
+            TestLeaf(u64::from_le_bytes(buf))
+        }
+    }
+
+    type SerializableSMT = SparseMerkleTree<TestLeaf, Hash64, TestHasher>;
+
+    #[test]
+    fn test_serialize_deserialize_round_trip() {
+        let mut tree = SerializableSMT::new(4);
+        tree.insert(0, TestLeaf(1));
+        tree.insert(5, TestLeaf(2));
+        tree.insert(9, TestLeaf(3));
+        let root = tree.root_hash();
+
+        let mut bytes = Vec::new();
+        tree.serialize(&mut bytes);
+
+        let mut restored = SerializableSMT::deserialize(&mut &bytes[..]);
+        assert_eq!(restored.position(), 3);
+        assert_eq!(restored.root_hash(), root);
+    }
+
     #[test]
     fn x1() {
+        // overwriting an already-inserted index must invalidate its cached
+        // ancestors, not just the path of a brand-new insert.
         let mut tree = TestSMT::new(3);
 
-        tree.insert(0,  TestLeaf(1));
-        println!("{}", tree.root_hash());
+        tree.insert(0, TestLeaf(1));
+        let root_a = tree.root_hash();
 
         tree.insert(0, TestLeaf(2));
-        println!("{}", tree.root_hash());
+        let root_b = tree.root_hash();
+
+        assert_ne!(root_a, root_b);
+
+        let mut fresh = TestSMT::new(3);
+        fresh.insert(0, TestLeaf(2));
+        assert_eq!(root_b, fresh.root_hash());
     }
 
 }